@@ -1,3 +1,4 @@
+import json
 import os
 import pathlib
 import pdb
@@ -5,12 +6,14 @@ import re
 import sys
 import time
 from datetime import datetime
+from getpass import getpass
 from inspect import getsourcefile
 
 import yaml
 from PyPDF2 import PdfFileMerger
+from PyPDF2.utils import PdfReadError
 from selenium import webdriver
-from selenium.common.exceptions import NoSuchElementException, TimeoutException
+from selenium.common.exceptions import StaleElementReferenceException, TimeoutException, WebDriverException
 from selenium.webdriver.common.by import By
 from selenium.webdriver.support import expected_conditions as EC
 from selenium.webdriver.support.ui import Select, WebDriverWait
@@ -20,34 +23,152 @@ try:
 except ImportError:
     from yaml import Loader
 
+# The most attachments downloaded for a single month
+MAX_ATTACHMENTS = 10
+
+# How many times a month is downloaded before settling for the attachments which did arrive, or giving up if none did
+MAX_ATTEMPTS = 3
+
+# How many downloads of a month may fail to be a PDF before the month is given up on
+MAX_DISCARDS = 3
+
 def num_months(m1: datetime, m2: datetime):
     return (m1.year - m2.year) * 12 + m1.month - m2.month
 
+def month_date(month: int):
+    """ Returns the year and month of a month counted backwards from the current one """
+
+    year, index = divmod(datetime.now().year * 12 + datetime.now().month - 1 - month, 12)
+
+    return year, index + 1
+
+def month_label(month: int):
+    """ Formats a month, counted backwards from the current one, the same way as in the configuration """
+
+    year, index = month_date(month)
+
+    return f"{index:02}/{year}"
+
+def account_number(account: str):
+    """ Returns the 11 digits of an account number, which may be written with or without the dots """
+
+    # YAML turns unquoted numbers into integers, which loses any leading zeros
+    if not isinstance(account, str):
+        raise ValueError(f"The account number {account} has to be quoted in the configuration, e.g. \"{account}\"")
+
+    # Kept as a string so that leading zeros are preserved
+    digits = account.replace('.', '')
+
+    if not (digits.isdigit() and len(digits) == 11):
+        raise ValueError(f"'{account}' is not a valid account number. It has to be 11 digits, e.g. ####.##.#####")
+
+    return digits
+
+def expand_path(path: str):
+    """ Expands ~ and environment variables such as $HOME in a path, the same way a shell would """
+
+    return os.path.expandvars(os.path.expanduser(path))
+
 def process_config(config):
-    for entry in config['extraction']:
-        entry['months'] = range(*(num_months(datetime.now(), datetime.strptime(x, "%m/%Y")) for x in (entry['from'], entry['to'])), -1)
+    for key in ('geckodriver', 'trace_dir', 'screenshot_dir', 'dump_capabilities'):
+        if config.get(key):
+            config[key] = expand_path(config[key])
 
-def resolve_env():
-    """ Adds the web drivers necessary for Selenium to work at runtime """
-    
-    basepath = f"{os.path.dirname(os.path.abspath(getsourcefile(lambda:0)))}/drivers"
+    # Listing the document types needs no extractions
+    for entry in config.get('extraction', []):
+        if entry.get('output_dir'):
+            entry['output_dir'] = expand_path(entry['output_dir'])
+
+        # Catch malformed account numbers before logging in
+        for account in entry['accounts']:
+            account_number(account)
+
+        # Leave out months the user doesn't want, such as ones which are already filed
+        # An empty exclude field is loaded as None
+        entry['excluded'] = [num_months(datetime.now(), datetime.strptime(x, "%m/%Y")) for x in (entry.get('exclude') or [])]
+
+        # The months are looked up on the website once the account has been selected
+        if entry.get('all') or entry.get('latest'):
+            entry['months'] = None
+            continue
+
+        # A year is shorthand for all of its months, which is what's needed for the tax return
+        if 'year' in entry:
+            year = datetime.now().year - 1 if entry['year'] == 'previous' else int(entry['year'])
+            entry['from'], entry['to'] = f"01/{year}", f"01/{year + 1}"
+
+        start, stop = (num_months(datetime.now(), datetime.strptime(x, "%m/%Y")) for x in (entry['from'], entry['to']))
+
+        # The last month is excluded, except when the range is a single month which would otherwise be empty
+        if start == stop:
+            stop -= 1
+
+        entry['months'] = range(start, stop, -1)
+
+        for x, month in zip(entry.get('exclude') or [], entry['excluded']):
+            if month not in entry['months']:
+                raise ValueError(f"The excluded month {x} is not within {entry['from']} to {entry['to']}")
+
+        entry['months'] = [x for x in entry['months'] if x not in entry['excluded']]
+
+def driver_dir(basepath: str, platform: str):
+    """ Finds the folder with the bundled web driver for a platform, which is checked to contain the driver.
+    Returns None when there are no bundled drivers for the platform """
 
-    if any([sys.platform.startswith(x) for x in ['freebsd', 'linux', 'aix']]):
+    if any([platform.startswith(x) for x in ['freebsd', 'linux', 'aix']]):
         # Linux
-        os.environ['PATH'] += f":{basepath}/linux/"
-    elif sys.platform.startswith('darwin'):
+        os_dir, executable = "linux", "geckodriver"
+    elif platform.startswith('darwin'):
         # Unix
-        os.environ['PATH'] += f":{basepath}/macos/"
-    elif sys.platform.startswith('win'):
+        os_dir, executable = "macos", "geckodriver"
+    elif platform.startswith('win'):
         # Windows
-        os.environ['PATH'] += f";{basepath}/windows/"
+        os_dir, executable = "windows", "geckodriver.exe"
+    else:
+        return None
+
+    # Check each level of the expected layout separately so the user knows exactly what is missing
+    expected = f"Expected layout: drivers/{os_dir}/{executable}"
+    if not os.path.isdir(basepath):
+        raise FileNotFoundError(f"Could not find the drivers folder at {basepath}. {expected}")
+    if not os.path.isdir(f"{basepath}/{os_dir}"):
+        raise FileNotFoundError(f"Could not find the folder {basepath}/{os_dir}. The folder for this platform has to be called '{os_dir}'. {expected}")
+    if not os.path.isfile(f"{basepath}/{os_dir}/{executable}"):
+        raise FileNotFoundError(f"Could not find {executable} in {basepath}/{os_dir}. {expected}")
+
+    return f"{basepath}/{os_dir}/"
+
+def resolve_env(basepath: str = "", platform: str = sys.platform):
+    """ Adds the web drivers necessary for Selenium to work at runtime """
+    
+    # The bundled drivers are next to this file
+    basepath = basepath or f"{os.path.dirname(os.path.abspath(getsourcefile(lambda:0)))}/drivers"
+
+    path = driver_dir(basepath, platform)
+    if path is None:
+        print(f"There are no bundled drivers for the platform '{platform}', so geckodriver has to be on the PATH or set with the geckodriver field in the config")
+        return
 
-def configure():
+    os.environ['PATH'] += f"{os.pathsep}{path}"
+
+def check_driver(path: str):
+    """ Ensures that a user supplied geckodriver exists and can be executed """
+
+    if not os.path.isfile(path):
+        raise FileNotFoundError(f"Could not find the geckodriver at {path}")
+    if not os.access(path, os.X_OK):
+        raise PermissionError(f"The geckodriver at {path} is not executable")
+
+def configure(config):
     """ Configures the driver with the correct options """
     conf = {}
 
+    if config.get('geckodriver'):
+        conf['executable_path'] = config['geckodriver']
+
     opt = webdriver.firefox.options.Options()
-    opt.headless = True
+    # The user has to see the browser to log in manually
+    opt.headless = not config.get('manual_login', False)
     prof = webdriver.FirefoxProfile()
 
     prof.set_preference('browser.download.folderList', 2)
@@ -58,6 +179,13 @@ def configure():
     prof.set_preference('plugin.scan.plid.all', False)
     prof.set_preference('plugin.scan.Acrobat', "99.0")
     prof.set_preference('general.warnOnAboutConfig', False)
+    # Newer versions of Firefox show a download panel which stalls headless runs
+    # Older versions ignore these preferences
+    prof.set_preference('browser.download.alwaysOpenPanel', False)
+    prof.set_preference('browser.download.improvements_to_download_panel', True)
+    prof.set_preference('browser.download.always_ask_before_handling_new_types', False)
+    if config.get('user_agent'):
+        prof.set_preference('general.useragent.override', config['user_agent'])
     prof.update_preferences()
 
     conf['firefox_profile'] = prof
@@ -66,16 +194,65 @@ def configure():
 
     return conf
 
-def login(driver, ssn: str = ""):
+def trace(driver, trace_dir: str, step: str):
+    """ Saves the page as it currently is, so the markup the crawler saw can be inspected when DNB changes its website """
+
+    if not trace_dir:
+        return
+
+    path = pathlib.Path(trace_dir)
+    path.mkdir(parents=True, exist_ok=True)
+
+    # Anything typed into the page, such as the SSN, is removed before it's written to disk
+    source = re.sub(r'(<input\b[^>]*?\bvalue=)("[^"]*"|\'[^\']*\')', r'\1""', driver.page_source)
+
+    # Numbered so the files sort in the order the steps happened
+    number = len(list(path.glob('*.html')))
+    with open(path / f"{number:03}_{step}.html", 'w', encoding='utf-8') as fo:
+        fo.write(source)
+
+def dismiss_consent(driver):
+    """ Removes the modal block that may appear when first visiting DNB """
+
+    if driver.find_element_by_id('consent-modal').is_displayed():
+        driver.find_element_by_id('consent-x').click()
+
+def wait_for_login(driver, message: str):
+    """ Waits for the logged in page so a failed login is reported with the given message instead of as a later navigation error """
+
+    # Wait for AJAX request to finish so that the required elements are present
+    try:
+        WebDriverWait(driver, 60).until(EC.presence_of_element_located((By.ID, "gllwg04e")))
+    except TimeoutException:
+        raise RuntimeError(message) from None
+
+def manual_login(driver, skip_consent: bool = False):
+    """ Opens DNB and lets the user log in themselves, for when the automated login no longer works """
+
+    driver.get("https://dnb.no")
+
+    if skip_consent:
+        print("Skipping the consent modal")
+    else:
+        dismiss_consent(driver)
+
+    input("Please log in manually and press Enter")
+
+    wait_for_login(driver, "Could not find the logged in page of DNB. Make sure you are fully logged in before pressing Enter")
+
+def login(driver, ssn: str = "", skip_consent: bool = False, trace_dir: str = ""):
     """ Navigates the user to DNB and logs them in using a PIN and OTP combo and waits for the content to load """
 
     print("Logging in")
 
     driver.get("https://dnb.no")
 
-    # Remove the modal block that may appear
-    if driver.find_element_by_id('consent-modal').is_displayed():
-        driver.find_element_by_id('consent-x').click()
+    if skip_consent:
+        print("Skipping the consent modal")
+    else:
+        dismiss_consent(driver)
+
+    trace(driver, trace_dir, "initial")
 
     # DNB has two stages of login
     # The first one is simply entering a user's SSN
@@ -84,19 +261,28 @@ def login(driver, ssn: str = ""):
     inp = form_1.find_element_by_xpath(".//input[@name='uid']")
     cnf = form_1.find_element_by_xpath(".//input[@id='loginFormSubmit'] | .//input[@name='Login']")
 
-    if not ssn:
-        ssn = input("Please enter your SSN for DNB: ")
+    # Mask the SSN so it isn't left readable on screen
+    while not ssn:
+        ssn = getpass("Please enter your SSN for DNB (11 digits): ")
+        if not (ssn.isdigit() and len(ssn) == 11):
+            print("The SSN has to be 11 digits")
+            ssn = ""
     inp.clear()
     inp.send_keys(ssn)
     cnf.click()
 
     # Wait for the necessary DOM elements to be loaded
     WebDriverWait(driver, 60).until(EC.presence_of_element_located((By.ID, "r_state-2")))
+    trace(driver, trace_dir, "first_login_stage")
 
     # Select the easier method of logging in and logging in
     nd_login = driver.find_element_by_xpath("//div[@id='r_state-2']")
     nd_login.find_element_by_xpath("./div[1]").click()
 
+    # The form slides open, so the inputs may not be usable right after the click
+    WebDriverWait(driver, 10).until(EC.element_to_be_clickable((By.ID, "phoneCode")))
+    WebDriverWait(driver, 10).until(EC.element_to_be_clickable((By.ID, "otpCode")))
+
     # Locate all the neccesary fields to log in with a PIN and OTP combo
     form_2 = nd_login.find_element_by_xpath("./div[2]//form")
     pin = form_2.find_element_by_xpath(".//input[@id='phoneCode']")
@@ -112,11 +298,10 @@ def login(driver, ssn: str = ""):
     # Login
     btn.click()
 
-    # Wait for AJAX request to finish so that the required elements are present
-    WebDriverWait(driver, 60).until(EC.presence_of_element_located((By.ID, "gllwg04e")))
+    wait_for_login(driver, "Could not log in to DNB. Check that the SSN, PIN and one time password are correct")
 
-def navigate(driver):
-    """ navigate to the correct part of the DNB website """
+def open_archive(driver):
+    """ Opens the archive and waits for its document type selector """
 
     print("Navigating")
 
@@ -129,99 +314,386 @@ def navigate(driver):
     # Locate the correct link
     m1.find_element_by_xpath(".//a[@id='gllwg07s']").click()
 
-    WebDriverWait(driver, 60).until(EC.presence_of_element_located((By.ID, "documentType-button")))
+    # A reload of the archive is usually enough when its render stalls
+    wait_for_control(driver, "documentType-button", "document type", lambda d: d.refresh())
+
+    reveal_select(driver, "documentType")
+
+def navigate(driver, config):
+    """ navigate to the correct part of the DNB website """
+
+    open_archive(driver)
 
-    driver.execute_script('document.getElementById("documentType").style = "display: block;"')
     sel = Select(driver.find_element_by_xpath("//select[@id='documentType'] | //select[@name='documentType']"))
-    sel.select_by_value('kontoutskrift')
+    sel.select_by_value(config.get('document_type', 'kontoutskrift'))
+
+def list_document_types(driver):
+    """ Prints the value and label of every document type in the archive, the values being what document_type takes """
+
+    open_archive(driver)
+
+    sel = Select(driver.find_element_by_xpath("//select[@id='documentType'] | //select[@name='documentType']"))
+    for option in sel.options:
+        print(f"{option.get_attribute('value')}: {option.get_attribute('textContent').strip()}")
+
+def wait_for_control(driver, element_id: str, description: str, recover, attempts: int = 3):
+    """ Waits for a control in the archive, calling recover with the driver before each new attempt.
+    Names the control if the archive never finishes loading """
+
+    for attempt in range(attempts):
+        try:
+            WebDriverWait(driver, 20).until(EC.presence_of_element_located((By.ID, element_id)))
+            return
+        except TimeoutException:
+            if attempt < attempts - 1:
+                print(f"The archive {description} control is not ready, reloading the archive")
+                recover(driver)
+
+    raise TimeoutException(f"The archive {description} control ({element_id}) never became ready")
+
+def reveal_select(driver, element_id: str):
+    """ DNB hides its select elements behind custom widgets, so they have to be shown before Selenium can use them """
+
+    # Some layouts use the native select elements, which can be used as they are
+    if driver.find_element_by_id(element_id).is_displayed():
+        return
+
+    driver.execute_script(f'document.getElementById("{element_id}").style = "display: block;"')
+
+def select_value(driver, element_id: str, value: str, timeout: int):
+    """ Selects an option in a select element once the option is present, as the options may be populated after the select is shown
+    Returns False if the select has options, but not the one asked for """
+
+    xpath = f"//select[@id='{element_id}'] | //select[@name='{element_id}']"
+
+    def options(d):
+        return [x.get_attribute('value') for x in Select(d.find_element_by_xpath(xpath)).options]
+
+    # Query the select anew on every attempt, as it may be replaced while the options are loaded
+    try:
+        WebDriverWait(driver, timeout, ignored_exceptions=[StaleElementReferenceException]).until(lambda d: value in options(d))
+    except TimeoutException:
+        # The options have loaded, they just don't include the value
+        if options(driver):
+            return False
+        raise
+
+    Select(driver.find_element_by_xpath(xpath)).select_by_value(value)
+    return True
+
+def report(config, message: str):
+    """ Prints a problem with an account or month, or ends the run with it when fast_fail is set """
+
+    if config.get('fast_fail'):
+        raise RuntimeError(message)
+
+    print(message)
 
 def extract(driver, config):
     """ Extract all the statements for the accounts given """
     print("Extracting")
 
-    file_pattern = re.compile('(\\d{11})_-_(\\d{4}-\\d{2}).*')
+    # The attachment links end with the document type, e.g. ajax/attachment/0/kontoutskrift
+    document_type = config.get('document_type', 'kontoutskrift')
+    attachment = f"//table//a[starts-with(@href, 'ajax/attachment/') and substring(@href, string-length(@href) - {len(document_type)}) = '/{document_type}']"
+
     for entry in config['extraction']:
         for account in entry['accounts']:
-            months = list(entry['months'])
-            # Wait to ensure that the correct DOM elements are loaded
-            WebDriverWait(driver, 60).until(EC.presence_of_element_located((By.ID, "documentType-button")))
-            WebDriverWait(driver, 60).until(EC.presence_of_element_located((By.ID, "accountNumber")))
-
-            # Select the correct account
-            driver.execute_script('document.getElementById("accountNumber").style = "display: block;"')
-            sel = Select(driver.find_element_by_xpath("//select[@id='accountNumber'] | //select[@name='accountNumber']"))
-            sel.select_by_value(account.replace('.', ''))
-
-            # Iterate over the given months
-            # Goes until all the months have been extracted, even with timeouts
-            while months:
-                for month in months:
-                    try:
-                        WebDriverWait(driver, 5).until(EC.presence_of_element_located((By.ID, "searchIntervalIndex")))
-                        driver.execute_script('document.getElementById("searchIntervalIndex").style = "display: block;"')
-                        sel = Select(driver.find_element_by_xpath("//select[@id='searchIntervalIndex'] | //select[@name='searchIntervalIndex']"))
-                        sel.select_by_value(f"{month}")
-
-                        WebDriverWait(driver, 5).until(EC.presence_of_element_located((By.XPATH, "//input[@id='archiveSearchSubmit']")))
-                        driver.find_element_by_xpath("//input[@id='archiveSearchSubmit']").click()
-
-                        # Wait to ensure that the correct DOM elements are loaded
-                        WebDriverWait(driver, 5).until(EC.presence_of_element_located((By.XPATH, "//table//a[@href='ajax/attachment/0/kontoutskrift'] | //div[@id='userInformationView']")))
+            # A failing account shouldn't lose the statements of the others
+            try:
+                months = extract_account(driver, config, entry, account, attachment)
+                combine(account, entry, config, months)
+            except WebDriverException as e:
+                if config.get('fast_fail'):
+                    raise
+
+                # Plain timeouts come without a message
+                print(f"Failed to extract the statements for {account}: {e.msg or type(e).__name__}")
+            except PdfReadError as e:
+                if config.get('fast_fail'):
+                    raise
+
+                print(f"Failed to combine the statements for {account}: {e}")
+
+def extract_account(driver, config, entry, account, attachment):
+    """ Extract the statements for a single account and returns the months which were looked for """
+
+    months = list(entry['months']) if entry['months'] is not None else None
+    discards = {}
+    attachments = {}
+    incomplete = {}
+    # Wait to ensure that the correct DOM elements are loaded
+    # Reloading the archive resets the document type, so it is opened again through the menu instead
+    wait_for_control(driver, "documentType-button", "document type", lambda d: navigate(d, config))
+    wait_for_control(driver, "accountNumber", "account", lambda d: navigate(d, config))
+
+    # Select the correct account
+    reveal_select(driver, "accountNumber")
+    if not select_value(driver, "accountNumber", account_number(account), 10):
+        # The account may be closed or mistyped, which shouldn't stop the other accounts
+        report(config, f"Could not find the account {account}")
+        return []
+
+    trace(driver, config.get('trace_dir', ""), f"account_{account_number(account)}")
+
+    # Lets users of headless runs confirm that the right page was reached
+    if config.get('screenshot_dir'):
+        pathlib.Path(config['screenshot_dir']).mkdir(parents=True, exist_ok=True)
+        driver.save_screenshot(str(pathlib.Path(config['screenshot_dir']) / f"{account}.png"))
+
+    if months is None:
+        months = [x for x in available_months(driver) if x not in entry['excluded']]
+
+        # The months are counted backwards from now, so the lowest are the most recent
+        if entry.get('latest'):
+            months = sorted(months)[:entry['latest']]
+
+        print(f"Found {len(months)} statement periods for {account}: {', '.join(month_label(x) for x in months)}")
+
+    # The months are removed from the list as they are downloaded
+    periods = list(months)
+
+    # Iterate over the given months
+    # Goes until all the months have been extracted, with a few attempts for the ones which time out
+    while months:
+        clicked = []
+        # Iterate over a copy, as months are removed along the way
+        for month in list(months):
+            try:
+                WebDriverWait(driver, 5).until(EC.presence_of_element_located((By.ID, "searchIntervalIndex")))
+                reveal_select(driver, "searchIntervalIndex")
+
+                # Months outside of what DNB keeps, or in the future, will never be available
+                if not select_value(driver, "searchIntervalIndex", f"{month}", 5):
+                    report(config, f"DNB has no statements for {account} in {month_label(month)}")
+                    months.remove(month)
+                    continue
+
+                # A click while the previous search is still loading may be dropped
+                if config.get('spinner_xpath'):
+                    WebDriverWait(driver, 5).until(EC.invisibility_of_element_located((By.XPATH, config['spinner_xpath'])))
+                WebDriverWait(driver, 5).until(EC.element_to_be_clickable((By.XPATH, "//input[@id='archiveSearchSubmit']")))
+                driver.find_element_by_xpath("//input[@id='archiveSearchSubmit']").click()
+
+                # Wait to ensure that the correct DOM elements are loaded
+                WebDriverWait(driver, 5).until(EC.presence_of_element_located((By.XPATH, f"{attachment} | //div[@id='userInformationView']")))
                         
-                        try:
-                            # Click the file to download
-                            driver.find_element_by_xpath("//table//a[@href='ajax/attachment/0/kontoutskrift']").click()
-                        except NoSuchElementException:
-                            # Inform the user if it's not possible to download
-                            print(f"Could not find financial statement for {account} in {driver.find_element_by_id('searchIntervalIndex-button').text}")
-                            months.remove(month)
-                    except TimeoutException:
-                        print(f"Timed out for {account} on {driver.find_element_by_id('searchIntervalIndex-button').text}")
-                        pass
+                # Some months have more than one attachment, e.g. an annex to the statement
+                links = driver.find_elements_by_xpath(attachment)[:MAX_ATTACHMENTS]
+
+                # A month that is attempted again starts from scratch, so it isn't merged twice
+                for file, downloaded in statement_files(account):
+                    if downloaded == month:
+                        file.unlink()
+
+                # Click the files to download
+                for link in links:
+                    link.click()
+                attachments[month] = len(links)
+
+                if links:
+                    clicked.append(month)
+                else:
+                    # Inform the user if it's not possible to download
+                    report(config, f"Could not find financial statement for {account} in {driver.find_element_by_id('searchIntervalIndex-button').text}")
+                    months.remove(month)
+            except TimeoutException:
+                print(f"Timed out for {account} on {driver.find_element_by_id('searchIntervalIndex-button').text}")
+                pass
+
+        # The files can only be checked once Firefox is done with them
+        wait_for_downloads(account, clicked, config.get('download_timeout', 60))
+
+        downloaded = {}
+        for file, month in statement_files(account):
+            # DNB occasionally serves an error page in place of the statement
+            if not is_pdf(file, config.get('min_pdf_bytes', 1024)):
+                print(f"Discarding {file.name} as it is not a valid PDF")
+                file.unlink()
+
+                discards[month] = discards.get(month, 0) + 1
+                if discards[month] >= MAX_DISCARDS and month in months:
+                    report(config, f"Giving up on {file.name} as it was not a valid PDF after {MAX_DISCARDS} attempts")
+                    months.remove(month)
+                continue
+
+            downloaded[month] = downloaded.get(month, 0) + 1
+
+        # remove reference of the month once all of its attachments have been downloaded
+        for month in list(months):
+            count = downloaded.get(month, 0)
+            if count and count >= attachments.get(month, 1):
+                months.remove(month)
+                continue
+
+            # A month which keeps timing out or never produces a file would otherwise be tried forever
+            incomplete[month] = incomplete.get(month, 0) + 1
+            if incomplete[month] >= MAX_ATTEMPTS:
+                if count:
+                    report(config, f"Only {count} of {attachments.get(month, 1)} attachments were downloaded for {account} in {month_label(month)}")
+                else:
+                    report(config, f"Giving up on {account} in {month_label(month)} as nothing was downloaded after {MAX_ATTEMPTS} attempts")
+                months.remove(month)
+
+    return periods
+
+def statement_files(account: str):
+    """ Retrieves the finished downloads for an account along with the month each of them is for """
 
-                for file in pathlib.Path(os.getcwd()).glob('*.pdf'):
-                    match = file_pattern.search(file.stem)
+    file_pattern = re.compile('(\\d{11})_-_(\\d{4}-\\d{2}).*')
 
-                    if not match:
-                        continue
+    files = []
+    for file in pathlib.Path(os.getcwd()).glob('*.pdf'):
+        match = file_pattern.search(file.stem)
+
+        if not match or match.group(1) != account_number(account):
+            continue
+
+        # Firefox creates an empty file while the download is still in progress
+        if file.with_name(f"{file.name}.part").exists():
+            continue
+
+        files.append((file, num_months(datetime.now(), datetime.strptime(match.group(2), "%Y-%m"))))
+
+    return files
+
+def wait_for_downloads(account: str, months: list, timeout: int):
+    """ Waits until Firefox has started the downloads for the given months and finished all the downloads for an account,
+    or until the timeout in seconds has passed """
+
+    cwd = pathlib.Path(os.getcwd())
+    end = time.time() + timeout
+
+    # Clicking a link returns before Firefox has created the file, which would make the check below pass straight away
+    def started(month):
+        year, index = month_date(month)
+        return any(cwd.glob(f"*{account_number(account)}_-_{year}-{index:02}*.pdf*"))
+
+    while not all(started(x) for x in months):
+        if time.time() > end:
+            print(f"Some of the downloads for {account} did not start within {timeout} seconds")
+            return
+
+        time.sleep(0.5)
+
+    while any(cwd.glob(f"*{account_number(account)}_-_*.pdf.part")):
+        if time.time() > end:
+            print(f"Some of the downloads for {account} did not finish within {timeout} seconds")
+            return
+
+        time.sleep(0.5)
+
+def is_pdf(file: pathlib.Path, min_bytes: int):
+    """ Checks that a downloaded file is large enough and actually starts like a PDF """
+
+    if file.stat().st_size < min_bytes:
+        return False
 
-                    # remove reference of the file if the file has been downloaded
-                    if match.group(1) == account.replace('.', '') and (month := num_months(datetime.now(), datetime.strptime(match.group(2), "%Y-%m"))) in months:
-                        months.remove(month)
+    with open(file, 'rb') as fi:
+        return fi.read(4) == b'%PDF'
 
-            combine(account)
+def available_months(driver):
+    """ Retrieves every statement period DNB offers for the currently selected account """
 
-def combine(account):
-    """ Combines the downloaded pdfs into one and deletes the individual ones """
+    WebDriverWait(driver, 60).until(EC.presence_of_element_located((By.ID, "searchIntervalIndex")))
+    sel = Select(driver.find_element_by_xpath("//select[@id='searchIntervalIndex'] | //select[@name='searchIntervalIndex']"))
+
+    return [int(value) for x in sel.options if (value := x.get_attribute('value')).isdigit()]
+
+def combine(account, entry, config, months):
+    """ Combines the downloaded pdfs for the period of the extraction into one """
 
     print(f"Combining for {account}")
 
-    # Retrieve all the files pertaining to the account
-    dl_path = pathlib.Path(os.getcwd())
-    files = [x for x in dl_path.glob('*.pdf') if x.stem.startswith(account.replace('.', ''))]
+    # Every extraction of the account writes the same file unless it is named after the period,
+    # so that file is merged from all the months downloaded for the account so far
+    per_period = entry.get('latest') or (entry['months'] is not None and config.get('merge_per_period'))
+
+    # Retrieve all the files pertaining to the account within the period
+    # Earlier extractions of the same account leave their files until the cleanup, so with per period names only the months of this one are used
+    # Unfinished downloads and files which aren't PDFs would stop the merge, so they are left out
+    files = []
+    for file, month in statement_files(account):
+        if (month in months or not per_period) and is_pdf(file, config.get('min_pdf_bytes', 1024)):
+            files.append(file)
+
+    if not files:
+        print(f"No statements were downloaded for {account}")
+        return
 
     merger = PdfFileMerger()
 
+    # The file names start with the account and the month, so sorting them puts them in chronological order
     for file in sorted(files):
         merger.append(str(file))
 
+    # Firefox downloads everything to one folder, so only the merged PDF is placed in the extraction's own folder
+    out_path = pathlib.Path(entry.get('output_dir', os.getcwd()))
+    out_path.mkdir(parents=True, exist_ok=True)
+
     # Output the merged PDF
-    merger.write(f"{account}.pdf")
+    # Naming it after the period as well keeps several extractions of the same account apart
+    if entry.get('latest'):
+        merger.write(str(out_path / f"{account}_latest-{entry['latest']}.pdf"))
+    elif not per_period:
+        merger.write(str(out_path / f"{account}.pdf"))
+    else:
+        period = "-".join(datetime.strptime(entry[x], "%m/%Y").strftime("%Y%m") for x in ('from', 'to'))
+        merger.write(str(out_path / f"{account}_{period}.pdf"))
     merger.close()
 
-def cleanup():
+def cleanup(clean_partials: bool = False):
     """ A function who's whole point is to clean up files which may be missed in the combination step """
 
     print("Cleaning up remaining files")
 
     file_pattern = re.compile('(\\d{11})_-_(\\d{4}-\\d{2}).*')
 
+    # Interrupted downloads are left behind by Firefox as .part files
+    # These have to be handled before the completed files they belong to are removed
+    if clean_partials:
+        for file in pathlib.Path(os.getcwd()).glob('*.pdf.part'):
+            completed = file.with_suffix('')
+
+            if file_pattern.search(completed.stem) and (not completed.exists() or completed.stat().st_size == 0):
+                file.unlink()
+
     for file in pathlib.Path(os.getcwd()).glob('*.pdf'):
         match = file_pattern.search(file.stem)
 
         if match:
             file.unlink()
 
+def run(driver, config):
+    """ Logs in and extracts all the statements with an already started browser """
+
+    if config.get('page_load_timeout'):
+        driver.set_page_load_timeout(config['page_load_timeout'])
+    if config.get('script_timeout'):
+        driver.set_script_timeout(config['script_timeout'])
+    print(f"Using the user agent {driver.execute_script('return navigator.userAgent')}")
+
+    # The capabilities show the Firefox version and settings actually in effect, which helps with bug reports
+    if config.get('dump_capabilities'):
+        pathlib.Path(config['dump_capabilities']).parent.mkdir(parents=True, exist_ok=True)
+        with open(config['dump_capabilities'], 'w') as fo:
+            json.dump(driver.capabilities, fo, indent=4)
+
+    if config.get('manual_login'):
+        manual_login(driver, config.get('skip_consent', False))
+    else:
+        login(driver, config['ssn'], config.get('skip_consent', False), config.get('trace_dir', ""))
+    trace(driver, config.get('trace_dir', ""), "logged_in")
+
+    if config.get('list_document_types'):
+        list_document_types(driver)
+        return
+
+    navigate(driver, config)
+    trace(driver, config.get('trace_dir', ""), "archive")
+    extract(driver, config)
+    cleanup(config.get('clean_partials', False))
+
 def main(argv):
     if len(argv) < 2:
         print("You need to add the path to the configuration file.")
@@ -234,16 +706,23 @@ def main(argv):
 
     process_config(config)
 
-    resolve_env()
+    # A user supplied driver bypasses the bundled ones
+    if config.get('geckodriver'):
+        check_driver(config['geckodriver'])
+    else:
+        resolve_env()
 
     # Instantiate the web browser and navigate to DNB
-    driver = webdriver.Firefox(**configure())
-    login(driver, config['ssn'])
-    navigate(driver)
-    extract(driver, config)
-    cleanup()
-
-    driver.quit()
+    driver = webdriver.Firefox(**configure(config))
+
+    try:
+        run(driver, config)
+    finally:
+        # The browser is shut down even when the run fails, and a failure here shouldn't hide the original error
+        try:
+            driver.quit()
+        except WebDriverException as e:
+            print(f"Could not shut down the browser cleanly: {e.msg}")
 
 if __name__ == '__main__':
     main(sys.argv)
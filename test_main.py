@@ -0,0 +1,61 @@
+import os
+import tempfile
+import unittest
+from unittest import mock
+
+import main
+
+
+class DriverDirTest(unittest.TestCase):
+    """ Runs the bundled driver lookup against a temporary drivers folder """
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.basepath = f"{self.tmp.name}/drivers"
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def make_driver(self, os_dir: str, executable: str):
+        os.makedirs(f"{self.basepath}/{os_dir}")
+        open(f"{self.basepath}/{os_dir}/{executable}", 'w').close()
+
+    def test_returns_the_platform_folder(self):
+        for platform, os_dir, executable in [("linux", "linux", "geckodriver"), ("freebsd13", "linux", "geckodriver"),
+                                             ("darwin", "macos", "geckodriver"), ("win32", "windows", "geckodriver.exe")]:
+            with self.subTest(platform=platform):
+                if not os.path.isdir(f"{self.basepath}/{os_dir}"):
+                    self.make_driver(os_dir, executable)
+                self.assertEqual(main.driver_dir(self.basepath, platform), f"{self.basepath}/{os_dir}/")
+
+    def test_unknown_platform(self):
+        self.assertIsNone(main.driver_dir(self.basepath, "haiku"))
+
+    def test_missing_drivers_folder(self):
+        with self.assertRaisesRegex(FileNotFoundError, "Could not find the drivers folder"):
+            main.driver_dir(self.basepath, "linux")
+
+    def test_missing_platform_folder(self):
+        self.make_driver("macos", "geckodriver")
+        with self.assertRaisesRegex(FileNotFoundError, "has to be called 'linux'"):
+            main.driver_dir(self.basepath, "linux")
+
+    def test_missing_executable(self):
+        os.makedirs(f"{self.basepath}/windows")
+        with self.assertRaisesRegex(FileNotFoundError, "Could not find geckodriver.exe"):
+            main.driver_dir(self.basepath, "win32")
+
+    def test_path_keeps_existing_entries(self):
+        self.make_driver("linux", "geckodriver")
+        with mock.patch.dict(os.environ, {'PATH': f"/usr/bin{os.pathsep}/bin"}):
+            main.resolve_env(self.basepath, "linux")
+            self.assertEqual(os.environ['PATH'], f"/usr/bin{os.pathsep}/bin{os.pathsep}{self.basepath}/linux/")
+
+    def test_path_unchanged_for_unknown_platform(self):
+        with mock.patch.dict(os.environ, {'PATH': "/usr/bin"}):
+            main.resolve_env(self.basepath, "haiku")
+            self.assertEqual(os.environ['PATH'], "/usr/bin")
+
+
+if __name__ == '__main__':
+    unittest.main()